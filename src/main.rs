@@ -1,40 +1,145 @@
 use anyhow::{bail, Result as AnyResult};
 use bytes::Bytes;
+use flate2::read::GzDecoder;
 use image::imageops::FilterType;
 use image::io::Reader as ImageReader;
 use image::{GenericImageView, ImageOutputFormat};
 use log::{error, info, warn};
+use rlottie::{Animation, Surface};
+use serde_json::Value;
+use std::collections::HashMap;
 use std::fmt::Display;
-use std::io::Cursor;
+use std::io::{Cursor, Read};
 use std::path::Path;
 use std::process::Stdio;
+use std::sync::Arc;
 use teloxide::net::Download;
 use teloxide::prelude::*;
-use teloxide::types::{File as TgFile, InputFile};
+use teloxide::types::{ChatId, File as TgFile, InputFile, UserId};
 use tempfile::NamedTempFile;
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
 use tokio::process::Command;
+use tokio::sync::Mutex;
 use webp::Encoder as WebpEncoder;
 
 const MAX_SIZE: u32 = 10 << 20;
 const MAX_OUTPUT_WEBM_SIZE: usize = 256 * 1000;
 
+// Lower bound for target_bitrate_bps. With the duration capped at 3s the computed budget stays
+// above ~628 kbps, so this floor only takes effect if that cap or MAX_OUTPUT_WEBM_SIZE changes.
+const MIN_BITRATE_BPS: u64 = 64_000;
+
 const FFMPEG: &str = "ffmpeg";
+const FFPROBE: &str = "ffprobe";
+
+// Telegram's Lottie animated stickers render to this fixed canvas.
+const TGS_SIZE: u32 = 512;
+
+const DEFAULT_EMOJI: &str = "😀";
+
+// A pack in progress for a chat. `name` is derived from the owner and bot username as soon as
+// /newpack runs, but `created` stays false until the first media arrives, since
+// createNewStickerSet (unlike addStickerToSet) requires an initial sticker.
+struct ChatPack {
+    owner: UserId,
+    title: String,
+    name: String,
+    created: bool,
+    emoji: String,
+}
+
+type PackStates = Arc<Mutex<HashMap<ChatId, ChatPack>>>;
+
+const FFMPEG_INPUT_ARGS: &[&str] = &["-hide_banner", "-t", "3", "-i"];
+const FFMPEG_OUTPUT_TAIL: &[&str] = &["-c:v", "libvpx-vp9", "-f", "webm", "-an", "-"];
+
+// Telegram video stickers must be exactly 512px on the major side, have even dimensions (VP9
+// requirement) and run at <=30fps. `-2` scales the minor side to the nearest multiple of 2
+// that preserves aspect ratio.
+const STICKER_SCALE_FILTER: &str =
+    "scale='if(gt(iw,ih),512,-2)':'if(gt(iw,ih),-2,512)'";
+
+const MAX_STICKER_FPS: f64 = 30.0;
 
-const FFMPEG_ARGS: (&[&str], &[&str]) = (
-    &["-hide_banner", "-t", "3", "-i"],
-    &[
-        "-vf",
-        "scale=w=512:h=512:force_original_aspect_ratio=decrease",
-        "-c:v",
-        "libvpx-vp9",
-        "-f",
-        "webm",
-        "-an",
-        "-",
-    ],
-);
+/// Subset of `ffprobe -show_format -show_streams` we care about.
+struct VideoProbe {
+    duration: f64,
+    has_video: bool,
+    width: u32,
+    height: u32,
+    fps: f64,
+}
+
+// Builds the `-vf` value that normalizes any source into Telegram's video-sticker constraints
+// for the mp4/GIF path (driven by ffprobe's source fps). The rawvideo-from-Lottie path builds
+// its own filter but caps fps with the same clamp_fps.
+fn sticker_scale_filter(source_fps: f64) -> String {
+    let fps = clamp_fps(source_fps);
+    format!("{STICKER_SCALE_FILTER},fps={fps}")
+}
+
+fn clamp_fps(fps: f64) -> u32 {
+    fps.max(1.0).min(MAX_STICKER_FPS).round() as u32
+}
+
+fn parse_r_frame_rate(s: &str) -> Option<f64> {
+    let (num, den) = s.split_once('/')?;
+    let (num, den) = (num.parse::<f64>().ok()?, den.parse::<f64>().ok()?);
+    (den != 0.0).then(|| num / den)
+}
+
+async fn probe_video(file: &Path) -> AnyResult<VideoProbe> {
+    let out = Command::new(FFPROBE)
+        .args(["-v", "quiet", "-print_format", "json", "-show_format", "-show_streams"])
+        .arg(file)
+        .stdout(Stdio::piped())
+        .spawn()?
+        .wait_with_output()
+        .await?;
+
+    if !out.status.success() {
+        error!("ffprobe failed: {:?}", out.status);
+        bail!("ffprobe")
+    }
+
+    let v: Value = serde_json::from_slice(&out.stdout)?;
+    let duration = v["format"]["duration"]
+        .as_str()
+        .and_then(|s| s.parse::<f64>().ok())
+        .unwrap_or(0.0);
+    let video_stream = v["streams"]
+        .as_array()
+        .and_then(|streams| streams.iter().find(|s| s["codec_type"] == "video"));
+    let (width, height, fps) = match video_stream {
+        Some(s) => (
+            s["width"].as_u64().unwrap_or(0) as u32,
+            s["height"].as_u64().unwrap_or(0) as u32,
+            s["r_frame_rate"]
+                .as_str()
+                .and_then(parse_r_frame_rate)
+                .unwrap_or(MAX_STICKER_FPS),
+        ),
+        None => (0, 0, MAX_STICKER_FPS),
+    };
+
+    Ok(VideoProbe {
+        duration,
+        has_video: video_stream.is_some(),
+        width,
+        height,
+        fps,
+    })
+}
+
+// Targets a single-pass encode that lands under MAX_OUTPUT_WEBM_SIZE: size budget in bits,
+// spread over the (at most 3s, due to FFMPEG_INPUT_ARGS) trimmed duration, with a 0.92 safety
+// margin for container/keyframe overhead.
+fn target_bitrate_bps(probe: &VideoProbe) -> u64 {
+    let t = probe.duration.min(3.0).max(0.1);
+    let bps = (MAX_OUTPUT_WEBM_SIZE as f64 * 8.0 * 0.92 / t) as u64;
+    bps.max(MIN_BITRATE_BPS)
+}
 
 #[derive(Debug)]
 struct BadRequest {
@@ -53,6 +158,22 @@ impl BadRequest {
     }
 }
 
+// webp::Encoder sometimes fails with Unimplemented when inputting small images.
+fn encode_image(img: &image::DynamicImage) -> AnyResult<(Bytes, &'static str)> {
+    Ok(match WebpEncoder::from_image(img) {
+        Ok(webp) => {
+            let mem = webp.encode_lossless();
+            (Bytes::copy_from_slice(&*mem), "webp")
+        }
+        Err(e) => {
+            warn!("webp: {}, falling back to png", e);
+            let mut v = Cursor::new(Vec::with_capacity(60000));
+            img.write_to(&mut v, ImageOutputFormat::Png)?;
+            (v.into_inner().into(), "png")
+        }
+    })
+}
+
 async fn process_image(file: Vec<u8>) -> AnyResult<(Bytes, &'static str)> {
     match ImageReader::new(Cursor::new(file))
         .with_guessed_format()
@@ -62,19 +183,7 @@ async fn process_image(file: Vec<u8>) -> AnyResult<(Bytes, &'static str)> {
         Ok(img) => {
             info!("got img of {:?}", img.dimensions());
             let img = img.resize(512, 512, FilterType::Lanczos3);
-            // webp::Encoder sometimes fails with Unimplemented when inputting small images.
-            return Ok(match WebpEncoder::from_image(&img) {
-                Ok(webp) => {
-                    let mem = webp.encode_lossless();
-                    (Bytes::copy_from_slice(&*mem), "webp")
-                }
-                Err(e) => {
-                    warn!("webp: {}, falling back to png", e);
-                    let mut v = Cursor::new(Vec::with_capacity(60000));
-                    img.write_to(&mut v, ImageOutputFormat::Png)?;
-                    (v.into_inner().into(), "png")
-                }
-            });
+            encode_image(&img)
         }
         Err(e) => {
             info!("decode failed: {}", e);
@@ -83,74 +192,385 @@ async fn process_image(file: Vec<u8>) -> AnyResult<(Bytes, &'static str)> {
     }
 }
 
+async fn run_ffmpeg_encode(file: &Path, source_fps: f64, bitrate_bps: u64) -> AnyResult<Vec<u8>> {
+    let bitrate = format!("{bitrate_bps}");
+    let vf = sticker_scale_filter(source_fps);
+    let out = Command::new(FFMPEG)
+        .args(FFMPEG_INPUT_ARGS)
+        .arg(file)
+        .args(["-vf", &vf])
+        .args(FFMPEG_OUTPUT_TAIL)
+        .args(["-b:v", &bitrate, "-maxrate", &bitrate, "-bufsize", &bitrate])
+        .stdout(Stdio::piped())
+        .spawn()?
+        .wait_with_output()
+        .await?;
+
+    if !out.status.success() {
+        error!("ffmpeg failed: {:?}", out.status);
+        bail!("ffmpeg")
+    }
+    Ok(out.stdout)
+}
+
 // Passing a mp4 video from pipe sometimes causes failure in codecs detection of ffmpeg, so we have
 // to use a temporary file.
-async fn process_video(file: &Path) -> AnyResult<(Bytes, &'static str)> {
-    // FIXME: output could be still too big even when lossy, try specify a bit rate?
-    // FIXME: current implementation often has to run ffmpeg twice, try to avoid the lossless
-    //        attempt in such cases.
-
-    let mut lossy = false;
-    loop {
-        let mut cmd = Command::new(FFMPEG);
-        let mut cmd = cmd.args(FFMPEG_ARGS.0).arg(file);
-        if !lossy {
-            cmd = cmd.arg("-lossless").arg("1");
-        }
-        let out = cmd
-            .args(FFMPEG_ARGS.1)
-            .stdout(Stdio::piped())
-            .spawn()?
-            .wait_with_output()
-            .await?;
-
-        if !out.status.success() {
-            error!("ffmpeg failed: {:?}", out.status);
-            bail!("ffmpeg")
-        }
-        if !lossy && out.stdout.len() > MAX_OUTPUT_WEBM_SIZE {
-            lossy = true;
-            info!("retrying with lossy");
-        } else {
-            return Ok((Bytes::from(out.stdout), "webm"));
-        }
+async fn process_video(file: &Path, probe: &VideoProbe) -> AnyResult<(Bytes, &'static str)> {
+    let bitrate = target_bitrate_bps(probe);
+    let out = run_ffmpeg_encode(file, probe.fps, bitrate).await?;
+
+    // A long/high-motion clip can still overshoot the bitrate-targeted encode (VP9's rate
+    // control isn't exact); fall back to one stricter second pass at half the bitrate. Its
+    // output is returned as is, without another size check.
+    let out = if out.len() > MAX_OUTPUT_WEBM_SIZE {
+        info!(
+            "bitrate-targeted encode still too big ({} bytes), retrying stricter",
+            out.len()
+        );
+        run_ffmpeg_encode(file, probe.fps, bitrate / 2).await?
+    } else {
+        out
+    };
+
+    Ok((Bytes::from(out), "webm"))
+}
+
+const THUMBNAIL_SIZE: u32 = 100;
+
+// Grabs a single frame near the midpoint of the clip and runs it through the same
+// webp-with-png-fallback encoder process_image uses, to get a representative static preview.
+async fn make_thumbnail(file: &Path, duration: f64) -> AnyResult<(Bytes, &'static str)> {
+    let ss = format!("{}", (duration / 2.0).max(0.0));
+    let scale = format!(
+        "scale=w={THUMBNAIL_SIZE}:h={THUMBNAIL_SIZE}:force_original_aspect_ratio=decrease"
+    );
+    let out = Command::new(FFMPEG)
+        .args(["-hide_banner", "-ss", &ss, "-i"])
+        .arg(file)
+        .args([
+            "-frames:v",
+            "1",
+            "-vf",
+            &scale,
+            "-f",
+            "image2pipe",
+            "-vcodec",
+            "png",
+            "-",
+        ])
+        .stdout(Stdio::piped())
+        .spawn()?
+        .wait_with_output()
+        .await?;
+
+    if !out.status.success() {
+        error!("ffmpeg thumbnail failed: {:?}", out.status);
+        bail!("ffmpeg thumbnail")
     }
+
+    let img = ImageReader::new(Cursor::new(out.stdout))
+        .with_guessed_format()?
+        .decode()?;
+    encode_image(&img)
 }
 
-async fn handle_image(f: TgFile, bot: &AutoSend<Bot>) -> AnyResult<(Bytes, &'static str)> {
+// A .tgs file is just a gzip-compressed Lottie JSON document.
+fn gunzip_to_string(file: &[u8]) -> Option<String> {
+    let mut s = String::new();
+    GzDecoder::new(file).read_to_string(&mut s).ok()?;
+    Some(s)
+}
+
+fn looks_like_tgs(file_name: Option<&str>, file: &[u8]) -> Option<String> {
+    if file_name.map_or(false, |s| s.ends_with(".tgs")) {
+        return gunzip_to_string(file);
+    }
+    gunzip_to_string(file).filter(|s| s.trim_start().starts_with('{'))
+}
+
+async fn run_ffmpeg_rawvideo_encode(
+    frames: &[u8],
+    source_fps: f64,
+    target_fps: u32,
+    bitrate_bps: u64,
+) -> AnyResult<Vec<u8>> {
+    let bitrate = format!("{bitrate_bps}");
+    let size = format!("{TGS_SIZE}x{TGS_SIZE}");
+    let source_fps = format!("{source_fps}");
+    // Decimate down to target_fps via the fps= filter rather than just relabeling the output
+    // rate, so a Lottie authored above MAX_STICKER_FPS plays back at the right speed instead of
+    // running in slow motion.
+    let vf = format!("unpremultiply=inplace=1,format=rgba,fps={target_fps}");
+    let mut child = Command::new(FFMPEG)
+        // rlottie renders into a premultiplied-alpha ARGB32 surface, which in memory is BGRA
+        // byte order on little-endian hosts; unpremultiply and reorder to straight RGBA before
+        // handing frames to the VP9 encoder, or transparent edges come out dark/haloed.
+        .args(["-hide_banner", "-f", "rawvideo", "-pix_fmt", "bgra"])
+        .args(["-s", &size, "-r", &source_fps, "-i", "-"])
+        // Same 3s trim as the mp4/GIF path (FFMPEG_INPUT_ARGS), so the bitrate budgeted for
+        // at most 3s doesn't get spread over a much longer encode.
+        .args(["-t", "3", "-vf", &vf])
+        .args(FFMPEG_OUTPUT_TAIL)
+        .args(["-b:v", &bitrate, "-maxrate", &bitrate, "-bufsize", &bitrate])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    let mut stdin = child.stdin.take().expect("stdin was piped");
+    let frames = frames.to_vec();
+    let write = tokio::spawn(async move { stdin.write_all(&frames).await });
+    let out = child.wait_with_output().await?;
+
+    if !out.status.success() {
+        error!("ffmpeg failed: {:?}", out.status);
+        bail!("ffmpeg")
+    }
+
+    // With `-t 3`, ffmpeg stops reading stdin once it has enough frames and exits 0 while
+    // frames for a longer Lottie are still queued; the writer then sees BrokenPipe even though
+    // we already have a complete encode in `out.stdout`. A failed ffmpeg run has already
+    // bailed above; here BrokenPipe is ignored and any other write failure is propagated.
+    match write.await? {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    Ok(out.stdout)
+}
+
+// Decodes a Lottie animation (the gunzipped contents of a .tgs) and re-encodes it as the same
+// VP9 .webm the rest of the pipeline emits, so it rides the pack-building and size-capping logic
+// as any other converted video.
+async fn process_tgs(json: String) -> AnyResult<(Bytes, &'static str)> {
+    let animation = Animation::from_data(json, "tgs", "").ok_or_else(|| {
+        info!("failed to parse tgs as lottie");
+        anyhow::Error::new(BadRequest::new("File is not a valid animated sticker!"))
+    })?;
+
+    // Render at the Lottie's own frame rate; fps= decimation (not output relabeling) gets it
+    // down to a Telegram-compliant rate, so duration/playback speed stay correct either way.
+    let source_fps = animation.frame_rate().max(1.0);
+    let target_fps = clamp_fps(source_fps);
+    let total_frames = animation.totalframe();
+    let duration = total_frames as f64 / source_fps;
+
+    let mut surface = Surface::new((TGS_SIZE as usize, TGS_SIZE as usize));
+    let mut frames = Vec::with_capacity(total_frames * (TGS_SIZE * TGS_SIZE * 4) as usize);
+    for frame in 0..total_frames {
+        animation.render(frame, &mut surface);
+        frames.extend_from_slice(surface.data());
+    }
+
+    let probe = VideoProbe {
+        duration,
+        has_video: true,
+        width: TGS_SIZE,
+        height: TGS_SIZE,
+        fps: target_fps as f64,
+    };
+    let bitrate = target_bitrate_bps(&probe);
+    let out = run_ffmpeg_rawvideo_encode(&frames, source_fps, target_fps, bitrate).await?;
+
+    // Same stricter-retry fallback as process_video: VP9 rate control isn't exact, so a
+    // high-motion clip can still overshoot the bitrate-targeted encode.
+    let out = if out.len() > MAX_OUTPUT_WEBM_SIZE {
+        info!(
+            "tgs encode still too big ({} bytes), retrying stricter",
+            out.len()
+        );
+        run_ffmpeg_rawvideo_encode(&frames, source_fps, target_fps, bitrate / 2).await?
+    } else {
+        out
+    };
+
+    Ok((Bytes::from(out), "webm"))
+}
+
+// (converted data, its extension, an optional static preview thumbnail)
+type MediaOutput = (Bytes, &'static str, Option<(Bytes, &'static str)>);
+
+// Images and .tgs animated stickers both arrive as a single in-memory blob, so they share a
+// download step; which decoder runs is decided by file name or, failing that, by sniffing
+// whether the blob gunzips to a Lottie JSON document.
+async fn handle_image(
+    f: TgFile,
+    bot: &AutoSend<Bot>,
+    file_name: Option<&str>,
+) -> AnyResult<MediaOutput> {
     let mut v = Vec::with_capacity(f.file_size as usize);
     bot.download_file(&f.file_path, &mut v).await?;
     info!("downloaded {} bytes", v.len());
-    process_image(v).await
+    if let Some(json) = looks_like_tgs(file_name, &v) {
+        let (data, suf) = process_tgs(json).await?;
+        return Ok((data, suf, None));
+    }
+    let (data, suf) = process_image(v).await?;
+    Ok((data, suf, None))
 }
 
-async fn handle_video(f: TgFile, bot: &AutoSend<Bot>) -> AnyResult<(Bytes, &'static str)> {
+async fn handle_video(f: TgFile, bot: &AutoSend<Bot>, want_thumb: bool) -> AnyResult<MediaOutput> {
     let path = NamedTempFile::new()?.into_temp_path();
     let mut tmp = File::create(&path).await?;
     bot.download_file(&f.file_path, &mut tmp).await?;
     tmp.flush().await?;
     drop(tmp);
     info!("downloaded {} bytes", f.file_size);
-    process_video(&path).await
+
+    let probe = probe_video(&path).await?;
+    if !probe.has_video {
+        bail!(BadRequest::new("File has no video stream!"))
+    }
+    info!("probed {}x{}, {}s", probe.width, probe.height, probe.duration);
+
+    let (data, suf) = process_video(&path, &probe).await?;
+    // A pack-bound sticker never surfaces its thumbnail (webp/png isn't a valid set thumb for
+    // a webm set, per add_to_active_pack), so skip the extra ffmpeg spawn entirely in that case.
+    let thumb = if want_thumb {
+        match make_thumbnail(&path, probe.duration).await {
+            Ok(thumb) => Some(thumb),
+            Err(e) => {
+                warn!("thumbnail generation failed: {:?}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    Ok((data, suf, thumb))
 }
 
 async fn handle_media(
     file_id: &String,
     bot: &AutoSend<Bot>,
     is_video: bool,
-) -> AnyResult<(Bytes, &'static str)> {
+    file_name: Option<&str>,
+    want_thumb: bool,
+) -> AnyResult<MediaOutput> {
     let f = bot.get_file(file_id).await?;
     if f.file_size > MAX_SIZE {
         bail!("File too big")
     }
     if is_video {
-        handle_video(f, bot).await
+        handle_video(f, bot, want_thumb).await
     } else {
-        handle_image(f, bot).await
+        handle_image(f, bot, file_name).await
     }
 }
 
-async fn handler(msg: Message, bot: &AutoSend<Bot>) -> &'static str {
+// Sticker set short names must start with a letter and end in `_by_<botusername>`; the user id
+// alone isn't a valid prefix.
+fn sticker_set_name(owner: UserId, bot_username: &str) -> String {
+    format!("pack{}_by_{}", owner.0, bot_username)
+}
+
+async fn handle_newpack(
+    bot: &AutoSend<Bot>,
+    state: &PackStates,
+    bot_username: &str,
+    chat_id: ChatId,
+    owner: UserId,
+    title: &str,
+) -> AnyResult<String> {
+    if title.is_empty() {
+        bail!(BadRequest::new("Usage: /newpack <title>"));
+    }
+
+    let name = sticker_set_name(owner, bot_username);
+    let msg = match bot.get_sticker_set(name.clone()).await {
+        Ok(set) => {
+            state.lock().await.insert(
+                chat_id,
+                ChatPack {
+                    owner,
+                    title: set.title,
+                    name: name.clone(),
+                    created: true,
+                    emoji: DEFAULT_EMOJI.to_owned(),
+                },
+            );
+            if set.title == title {
+                format!("You already have a pack, resuming it: https://t.me/addstickers/{name}")
+            } else {
+                format!(
+                    "You already have a pack titled \"{}\"; resuming it and ignoring the new \
+                     title \"{title}\" (one pack per user): https://t.me/addstickers/{name}",
+                    set.title
+                )
+            }
+        }
+        Err(_) => {
+            state.lock().await.insert(
+                chat_id,
+                ChatPack {
+                    owner,
+                    title: title.to_owned(),
+                    name,
+                    created: false,
+                    emoji: DEFAULT_EMOJI.to_owned(),
+                },
+            );
+            "Pack started, send me media to add stickers to it.".to_owned()
+        }
+    };
+    Ok(msg)
+}
+
+async fn handle_emoji(state: &PackStates, chat_id: ChatId, emoji: &str) -> AnyResult<&'static str> {
+    if emoji.is_empty() {
+        bail!(BadRequest::new("Usage: /emoji <emoji>"));
+    }
+    let mut map = state.lock().await;
+    let pack = map
+        .get_mut(&chat_id)
+        .ok_or_else(|| anyhow::Error::new(BadRequest::new("Start a pack first with /newpack.")))?;
+    pack.emoji = emoji.to_owned();
+    Ok("Emoji updated for the next sticker.")
+}
+
+// Appends the already converted `data` to the chat's active pack, if any. The first sticker creates the
+// set (createNewStickerSet); later ones use addStickerToSet.
+async fn add_to_active_pack(
+    bot: &AutoSend<Bot>,
+    state: &PackStates,
+    chat_id: ChatId,
+    data: Bytes,
+    suf: &str,
+) -> AnyResult<Option<String>> {
+    let mut map = state.lock().await;
+    let pack = match map.get_mut(&chat_id) {
+        Some(pack) => pack,
+        None => return Ok(None),
+    };
+
+    // createNewStickerSet/addStickerToSet take no thumbnail parameter (set thumbs are changed
+    // separately via setStickerSetThumb), and our generated static thumbnail is a webp/png image
+    // anyway, which isn't a valid thumb for a webm video sticker set. Don't attach one here.
+    let sticker = InputFile::memory(data);
+    let is_webm = suf == "webm";
+    if pack.created {
+        let mut req = bot.add_sticker_to_set(pack.owner, pack.name.clone());
+        req = if is_webm {
+            req.webm_sticker(sticker)
+        } else {
+            req.png_sticker(sticker)
+        };
+        req.emojis(pack.emoji.clone()).await?;
+    } else {
+        let mut req =
+            bot.create_new_sticker_set(pack.owner, pack.name.clone(), pack.title.clone());
+        req = if is_webm {
+            req.webm_sticker(sticker)
+        } else {
+            req.png_sticker(sticker)
+        };
+        req.emojis(pack.emoji.clone()).await?;
+        pack.created = true;
+    }
+    Ok(Some(format!("https://t.me/addstickers/{}", pack.name)))
+}
+
+async fn handler(msg: Message, bot: &AutoSend<Bot>, state: &PackStates, bot_username: &str) -> String {
     let ch = &msg.chat;
     info!(
         "from {} {} (@{} {})",
@@ -159,6 +579,33 @@ async fn handler(msg: Message, bot: &AutoSend<Bot>) -> &'static str {
         ch.username().unwrap_or(""),
         ch.id.0
     );
+    if let Some(text) = msg.text() {
+        if text == "/newpack" || text.starts_with("/newpack ") {
+            let title = text["/newpack".len()..].trim();
+            let owner = match msg.from() {
+                Some(u) => u.id,
+                None => return "Can't tell who sent this.".to_owned(),
+            };
+            return match handle_newpack(bot, state, bot_username, ch.id, owner, title).await {
+                Ok(reply) => reply,
+                Err(e) => bad_request_message(e, "Failed to start pack."),
+            };
+        }
+        if text == "/emoji" || text.starts_with("/emoji ") {
+            let emoji = text["/emoji".len()..].trim();
+            return match handle_emoji(state, ch.id, emoji).await {
+                Ok(reply) => reply.to_owned(),
+                Err(e) => bad_request_message(e, "Failed to set emoji."),
+            };
+        }
+        if text == "/start" {
+            return "Hi! Send me an image, a GIF animation, or a .tgs animated sticker, and \
+                     I'll convert it for use with @Stickers. Use /newpack <title> first if you \
+                     want me to build a sticker pack as you go."
+                .to_owned();
+        }
+    }
+
     let mut is_video = false;
     let (file_id, size, file_name) = if let Some(doc) = msg.document() {
         info!(
@@ -191,21 +638,41 @@ async fn handler(msg: Message, bot: &AutoSend<Bot>) -> &'static str {
         );
         is_video = true;
         (&ani.file_id, ani.file_size, &ani.file_name)
-    } else if Some("/start") == msg.text() {
-        return "Hi! Send me an image or a GIF animation, and I'll convert it for use with @Stickers.";
     } else {
         info!("invalid: {:#?}", msg);
-        return "Please send an image or an GIF animation.";
+        return "Please send an image, a GIF animation, or a .tgs animated sticker.".to_owned();
     };
 
     if size > MAX_SIZE {
-        return "File is too big.";
+        return "File is too big.".to_owned();
     }
 
-    match handle_media(file_id, bot, is_video).await {
-        Ok((f, suf)) => {
-            let n = f.len();
-            let f = InputFile::memory(f);
+    let has_active_pack = state.lock().await.contains_key(&ch.id);
+    match handle_media(file_id, bot, is_video, file_name.as_deref(), !has_active_pack).await {
+        Ok((data, suf, thumb)) => {
+            if has_active_pack {
+                // A pack is active: the converted sticker is appended to it instead of being
+                // echoed back as a loose document, so the user isn't sent a duplicate upload
+                // on every add.
+                match add_to_active_pack(bot, state, ch.id, data, suf).await {
+                    Ok(Some(link)) => {
+                        if let Err(e) = bot
+                            .send_message(msg.chat.id, format!("Added to your pack: {link}"))
+                            .await
+                        {
+                            error!("send_message: {:?}", e);
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        error!("add_to_active_pack: {:?}", e);
+                        return bad_request_message(e, "Failed to add to sticker pack.");
+                    }
+                }
+                return String::new();
+            }
+
+            let n = data.len();
             let mut out_name;
             if let Some(s) = file_name {
                 // s.replace('.', "_")
@@ -215,23 +682,34 @@ async fn handler(msg: Message, bot: &AutoSend<Bot>) -> &'static str {
                 out_name = "out.".to_owned();
             };
             out_name.push_str(suf);
-            let f = f.file_name(out_name);
+            let f = InputFile::memory(data).file_name(out_name);
             info!("sending {} bytes", n);
             if let Err(e) = bot.send_document(msg.chat.id, f).await {
                 error!("send_document: {:?}", e);
-                return "Failed to send file.";
+                return "Failed to send file.".to_owned();
+            }
+
+            if let Some((thumb_data, thumb_suf)) = thumb {
+                let thumb_name = format!("thumb.{thumb_suf}");
+                let thumb_file = InputFile::memory(thumb_data).file_name(thumb_name);
+                if let Err(e) = bot.send_document(msg.chat.id, thumb_file).await {
+                    error!("send_document (thumbnail): {:?}", e);
+                }
             }
         }
         Err(e) => {
             error!("handle: {:?}", e);
-            return if let Ok(e) = e.downcast::<BadRequest>() {
-                e.msg
-            } else {
-                "Something went wrong."
-            };
+            return bad_request_message(e, "Something went wrong.");
         }
     }
-    ""
+    String::new()
+}
+
+fn bad_request_message(e: anyhow::Error, fallback: &'static str) -> String {
+    match e.downcast::<BadRequest>() {
+        Ok(e) => e.msg.to_owned(),
+        Err(_) => fallback.to_owned(),
+    }
 }
 
 #[tokio::main]
@@ -245,18 +723,32 @@ async fn main() {
     let bot = Bot::from_env().auto_send();
     info!("bot started by {:?}", bot.inner().client());
 
-    teloxide::repl(bot, |msg: Message, bot: AutoSend<Bot>| async move {
-        tokio::spawn(async move {
-            let id = msg.chat.id;
-            let s = handler(msg, &bot).await;
-            if !s.is_empty() {
-                if let Err(e) = bot.send_message(id, s).await {
-                    error!("send_message: {:?}", e);
+    let bot_username = Arc::new(
+        bot.get_me()
+            .await
+            .expect("failed to fetch bot info")
+            .user
+            .username
+            .expect("bot has no username"),
+    );
+    let pack_states: PackStates = Arc::new(Mutex::new(HashMap::new()));
+
+    teloxide::repl(bot, move |msg: Message, bot: AutoSend<Bot>| {
+        let bot_username = bot_username.clone();
+        let pack_states = pack_states.clone();
+        async move {
+            tokio::spawn(async move {
+                let id = msg.chat.id;
+                let s = handler(msg, &bot, &pack_states, &bot_username).await;
+                if !s.is_empty() {
+                    if let Err(e) = bot.send_message(id, s).await {
+                        error!("send_message: {:?}", e);
+                    }
                 }
-            }
-        });
-        // TODO: join the spawned tasks when interrupted?
-        respond(())
+            });
+            // TODO: join the spawned tasks when interrupted?
+            respond(())
+        }
     })
     .await;
 }